@@ -17,11 +17,35 @@ fn hash<T: AsRef<[u8]>>(input: T, h_key: u64) -> u64 {
   hasher.finish()
 }
 
+/// Minimum filter size in bits; keeps tiny filters from degenerating into a handful of slots.
+const MIN_BITS: u64 = 512;
+
+/// Errors returned by fallible `Bloom` operations.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BloomError {
+  /// Two filters combined with `union_with`/`intersect_with` have different parameters.
+  Mismatched,
+  /// A deserialized filter has inconsistent or empty fields.
+  Invalid(&'static str),
+}
+
+impl fmt::Display for BloomError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BloomError::Mismatched => write!(f, "bloom filters have mismatched parameters"),
+      BloomError::Invalid(reason) => write!(f, "invalid bloom filter: {}", reason),
+    }
+  }
+}
+
+impl std::error::Error for BloomError {}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct Bloom<T: AsRef<[u8]>> {
   n_bits: u64,
   n_bits_set: AtomicU64,
-  hash_keys: Vec<u64>,
+  hash_keys: [u64; 2],
+  k: usize,
   bits: Vec<AtomicU64>,
   _marker: PhantomData<T>
 }
@@ -31,7 +55,7 @@ impl<T: AsRef<[u8]>> fmt::Debug for Bloom<T> {
     write!(
       f,
       "Bloom {{ num_hash_keys: {}, num_bits: {}, num_bit_sets: {}, bits: ",
-      self.hash_keys.len(),
+      self.k,
       self.n_bits,
       self.num_bits_set(),
     )?;
@@ -50,52 +74,56 @@ pub fn new(n_items: usize, false_rate: f64) ->Self{
     let mut m = (-(n_items as f64)*false_rate.ln()/(2f64.ln()*2f64.ln())).ceil();
     m = cmp::max(1, m as u64) as f64; // make sure m >= 1
     let k = (2f64.ln())*m/(n_items as f64).round();
-    let length = (m as u64 + 63)/64; // calculate the length of the AtomicU64 vector
+    // round the target bit count up to the next power of two (with a floor) so indexing can use
+    // a mask instead of a modulo on the hot path.
+    let size = cmp::max(MIN_BITS, (m as u64).next_power_of_two());
+    let length = size/64; // calculate the length of the AtomicU64 vector
     let mut r = rng();
-    let hash_keys: Vec<u64> = (0..k as usize).map(|_| r.random()).collect();
-    Bloom { 
-      n_bits: length*64, 
+    Bloom {
+      n_bits: size,
       n_bits_set: AtomicU64::new(0),
-      hash_keys,
+      hash_keys: [r.random(), r.random()],
+      k: cmp::max(1, k as usize),
       bits: (0..length).map(|_| AtomicU64::new(0)).collect(),
       _marker: PhantomData,
     }
   }
-  /// Computes the `u64` index and bitmask for a given input and hash key.
-  /// This is used to set or check the bit corresponding to the input.
-  fn bit_pos(&self, input: &T, h_key: u64) -> (usize, u64) {
-    let p = hash(input, h_key) % self.n_bits;
+  /// Derives the two base hashes for an item using the Kirsch–Mitzenmacher double-hashing scheme.
+  /// `h2` is forced odd so successive indices never collapse onto one slot.
+  fn base_hashes(&self, input: &T) -> (u64, u64) {
+    let h1 = hash(input, self.hash_keys[0]);
+    let h2 = hash(input, self.hash_keys[1]) | 1;
+    (h1, h2)
+  }
+  /// Computes the `u64` index and bitmask for the `i`-th derived bit position.
+  /// `n_bits` is a power of two, so the mapping is a mask rather than an integer modulo.
+  fn bit_pos(&self, h1: u64, h2: u64, i: usize) -> (usize, u64) {
+    let p = h1.wrapping_add((i as u64).wrapping_mul(h2)) & (self.n_bits - 1);
     let idx = p>>6;
     let mask  = 1u64 << (p &63);
     (idx as usize, mask)
   }
-  /// Sets the bit corresponding to the given input and hash key in the Bloom filter.
-  fn set_bit(&self, input: &T, h_key: u64) -> bool{
-    let (idx, mask) = self.bit_pos(input, h_key);
+  /// Sets the bit at the given index/mask, tracking newly-set bits.
+  fn set_bit(&self, idx: usize, mask: u64) {
     let prev = self.bits[idx].fetch_or(mask, Ordering::Relaxed);
-    let is_new = prev &mask == 0;
-    if is_new {
+    if prev &mask == 0 {
       self.n_bits_set.fetch_add(1, Ordering::Relaxed);
     }
-    is_new
-  }
-
-  /// Checks the bit corresponding to the given input and hash key in the Bloom filter.
-  fn check_bit(&self, input: &T, h_key: u64) -> bool{
-    let (idx, mask) = self.bit_pos(input, h_key);
-    let bit = self.bits[idx].load(Ordering::Relaxed) & mask;
-    bit > 0
   }
   /// Adds an item to Bloom filter
   pub fn insert(&self, item: &T) {
-    for h_key in &self.hash_keys {
-      self.set_bit(item, *h_key);
+    let (h1, h2) = self.base_hashes(item);
+    for i in 0..self.k {
+      let (idx, mask) = self.bit_pos(h1, h2, i);
+      self.set_bit(idx, mask);
     }
   }
   /// Checks if an item is in Bloom filter
   pub fn contains(&self, item: &T) -> bool {
-    for h_key in &self.hash_keys {
-      if !self.check_bit(item, *h_key) {
+    let (h1, h2) = self.base_hashes(item);
+    for i in 0..self.k {
+      let (idx, mask) = self.bit_pos(h1, h2, i);
+      if self.bits[idx].load(Ordering::Relaxed) & mask == 0 {
         return false;
       }
     }
@@ -111,37 +139,279 @@ pub fn new(n_items: usize, false_rate: f64) ->Self{
   pub fn num_bits_set(&self) ->u64 {
     self.n_bits_set.load(Ordering::Relaxed)
   }
+  /// Returns true when `other` has the same size and hash parameters as `self`, so that bitwise
+  /// combination is meaningful.
+  fn same_params(&self, other: &Bloom<T>) -> bool {
+    self.n_bits == other.n_bits && self.k == other.k && self.hash_keys == other.hash_keys
+  }
+  /// Recounts the set bits across every word and stores the result.
+  fn recount_bits_set(&self) {
+    let set: u64 = self.bits.iter().map(|w| w.load(Ordering::Relaxed).count_ones() as u64).sum();
+    self.n_bits_set.store(set, Ordering::Relaxed);
+  }
+  /// ORs the bits of `other` into `self`, producing a filter for the union of the two sets.
+  /// The filters must share the same size and hash keys.
+  pub fn union_with(&self, other: &Bloom<T>) -> Result<(), BloomError> {
+    if !self.same_params(other) {
+      return Err(BloomError::Mismatched);
+    }
+    for (w, o) in self.bits.iter().zip(&other.bits) {
+      w.fetch_or(o.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+    self.recount_bits_set();
+    Ok(())
+  }
+  /// ANDs the bits of `other` into `self`, approximating the intersection of the two sets.
+  /// The filters must share the same size and hash keys.
+  pub fn intersect_with(&self, other: &Bloom<T>) -> Result<(), BloomError> {
+    if !self.same_params(other) {
+      return Err(BloomError::Mismatched);
+    }
+    for (w, o) in self.bits.iter().zip(&other.bits) {
+      w.fetch_and(o.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+    self.recount_bits_set();
+    Ok(())
+  }
+  /// Estimates the number of distinct items inserted using `n ≈ -(m / k) * ln(1 - X/m)`,
+  /// where `m` is the bit count, `k` the number of hash functions and `X` the set-bit count.
+  pub fn estimate_cardinality(&self) -> f64 {
+    let m = self.n_bits as f64;
+    let k = self.k as f64;
+    let x = self.num_bits_set() as f64;
+    -(m / k) * (1.0 - x / m).ln()
+  }
+  /// Creates a filter with an optimal bit size, but clamps the bit count to `max_bits` so callers
+  /// with a hard memory budget get a predictable footprint. `k` is recomputed for the capped size,
+  /// trading a higher-but-known false-positive rate for bounded memory.
+  pub fn new_capped(n_items: usize, false_rate: f64, max_bits: u64) -> Self {
+    let n_items = cmp::max(1, n_items);
+    let mut m = (-(n_items as f64)*false_rate.ln()/(2f64.ln()*2f64.ln())).ceil();
+    m = cmp::max(1, m as u64) as f64;
+    // largest power of two that fits under the cap (but at least one word)
+    let cap = cmp::max(64, max_bits.next_power_of_two().min(max_bits));
+    let mut size = cmp::max(MIN_BITS, (m as u64).next_power_of_two());
+    if size > cap {
+      size = if cap.is_power_of_two() { cap } else { cap.next_power_of_two() >> 1 };
+    }
+    let size = cmp::max(64, size);
+    // recompute the optimal k for the (possibly smaller) capped size
+    let k = cmp::max(1, ((2f64.ln())*(size as f64)/(n_items as f64)).round() as usize);
+    let length = size/64;
+    let mut r = rng();
+    Bloom {
+      n_bits: size,
+      n_bits_set: AtomicU64::new(0),
+      hash_keys: [r.random(), r.random()],
+      k,
+      bits: (0..length).map(|_| AtomicU64::new(0)).collect(),
+      _marker: PhantomData,
+    }
+  }
+  /// Reconstructs a filter from raw parts (e.g. after deserialization), validating the fields
+  /// before returning so later `bit_pos` calls cannot build an invalid index mask or index out of
+  /// bounds.
+  pub fn from_parts(n_bits: u64, hash_keys: [u64; 2], k: usize, bits: Vec<u64>) -> Result<Self, BloomError> {
+    let n_bits_set: u64 = bits.iter().map(|w| w.count_ones() as u64).sum();
+    let bloom = Bloom {
+      n_bits,
+      n_bits_set: AtomicU64::new(n_bits_set),
+      hash_keys,
+      k,
+      bits: bits.into_iter().map(AtomicU64::new).collect(),
+      _marker: PhantomData,
+    };
+    bloom.validate()?;
+    Ok(bloom)
+  }
+  /// Checks that the filter's fields are internally consistent. A filter arriving from untrusted
+  /// serialized data must pass this before use.
+  pub fn validate(&self) -> Result<(), BloomError> {
+    if self.n_bits == 0 {
+      return Err(BloomError::Invalid("n_bits must be greater than zero"));
+    }
+    if !self.n_bits.is_power_of_two() {
+      return Err(BloomError::Invalid("n_bits must be a power of two"));
+    }
+    if self.bits.len() as u64 != self.n_bits.div_ceil(64) {
+      return Err(BloomError::Invalid("bits length inconsistent with n_bits"));
+    }
+    if self.k == 0 {
+      return Err(BloomError::Invalid("number of hash functions must be non-zero"));
+    }
+    Ok(())
+  }
+}
+
+/// The width of a single counter, in bits. 8-bit counters saturate at 255.
+const COUNTER_BITS: u64 = 8;
+/// How many counters are packed into each `AtomicU64` word.
+const COUNTERS_PER_WORD: u64 = 64 / COUNTER_BITS;
+/// The largest value a counter can hold; once reached it is never decremented.
+const COUNTER_MAX: u64 = (1 << COUNTER_BITS) - 1;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CountingBloom<T: AsRef<[u8]>> {
+  n_counters: u64,
+  n_nonzero: AtomicU64,
+  hash_keys: Vec<u64>,
+  counters: Vec<AtomicU64>,
+  _marker: PhantomData<T>
+}
+
+impl<T: AsRef<[u8]>> fmt::Debug for CountingBloom<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "CountingBloom {{ num_hash_keys: {}, num_counters: {}, num_bit_sets: {} }}",
+      self.hash_keys.len(),
+      self.n_counters,
+      self.num_bits_set(),
+    )
+  }
+}
+
+impl<T: AsRef<[u8]>> CountingBloom<T> {
+/// Creates a thread-safe counting Bloom filter with an optimal counter size and number of hash
+/// functions based on the expected number of items and the desired false positive rate.
+/// Unlike [`Bloom`], each slot is an 8-bit saturating counter, so items can be removed again.
+pub fn new(n_items: usize, false_rate: f64) ->Self{
+    let n_items = cmp::max(1, n_items);
+    let mut m = (-(n_items as f64)*false_rate.ln()/(2f64.ln()*2f64.ln())).ceil();
+    m = cmp::max(1, m as u64) as f64; // make sure m >= 1
+    let k = (2f64.ln())*m/(n_items as f64).round();
+    // round the counter count up to a whole number of words so packing is exact
+    let length = (m as u64).div_ceil(COUNTERS_PER_WORD);
+    let mut r = rng();
+    let hash_keys: Vec<u64> = (0..k as usize).map(|_| r.random()).collect();
+    CountingBloom {
+      n_counters: length*COUNTERS_PER_WORD,
+      n_nonzero: AtomicU64::new(0),
+      hash_keys,
+      counters: (0..length).map(|_| AtomicU64::new(0)).collect(),
+      _marker: PhantomData,
+    }
+  }
+  /// Computes the word index and bit offset of the counter for a given input and hash key.
+  fn counter_pos(&self, input: &T, h_key: u64) -> (usize, u64) {
+    let p = hash(input, h_key) % self.n_counters;
+    let idx = p/COUNTERS_PER_WORD;
+    let offset = (p%COUNTERS_PER_WORD)*COUNTER_BITS;
+    (idx as usize, offset)
+  }
+  /// Increments the counter for the given input and hash key, saturating at `COUNTER_MAX`.
+  fn incr_counter(&self, input: &T, h_key: u64) {
+    let (idx, offset) = self.counter_pos(input, h_key);
+    let mask = COUNTER_MAX << offset;
+    let mut cur = self.counters[idx].load(Ordering::Relaxed);
+    loop {
+      let val = (cur & mask) >> offset;
+      if val == COUNTER_MAX {
+        return; // saturated, leave it pinned
+      }
+      let next = (cur & !mask) | ((val + 1) << offset);
+      match self.counters[idx].compare_exchange_weak(cur, next, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => {
+          if val == 0 {
+            self.n_nonzero.fetch_add(1, Ordering::Relaxed);
+          }
+          return;
+        }
+        Err(actual) => cur = actual,
+      }
+    }
+  }
+  /// Decrements the counter for the given input and hash key. A counter that has saturated is
+  /// left untouched, because its true count is unknown and decrementing could cause a false
+  /// negative.
+  fn decr_counter(&self, input: &T, h_key: u64) {
+    let (idx, offset) = self.counter_pos(input, h_key);
+    let mask = COUNTER_MAX << offset;
+    let mut cur = self.counters[idx].load(Ordering::Relaxed);
+    loop {
+      let val = (cur & mask) >> offset;
+      if val == 0 || val == COUNTER_MAX {
+        return; // nothing to remove, or pinned at saturation
+      }
+      let next = (cur & !mask) | ((val - 1) << offset);
+      match self.counters[idx].compare_exchange_weak(cur, next, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => {
+          if val == 1 {
+            self.n_nonzero.fetch_sub(1, Ordering::Relaxed);
+          }
+          return;
+        }
+        Err(actual) => cur = actual,
+      }
+    }
+  }
+  /// Checks the counter for the given input and hash key is non-zero.
+  fn check_counter(&self, input: &T, h_key: u64) -> bool {
+    let (idx, offset) = self.counter_pos(input, h_key);
+    let mask = COUNTER_MAX << offset;
+    self.counters[idx].load(Ordering::Relaxed) & mask > 0
+  }
+  /// Adds an item to the counting Bloom filter.
+  pub fn insert(&self, item: &T) {
+    for h_key in &self.hash_keys {
+      self.incr_counter(item, *h_key);
+    }
+  }
+  /// Removes an item previously added to the counting Bloom filter.
+  pub fn remove(&self, item: &T) {
+    for h_key in &self.hash_keys {
+      self.decr_counter(item, *h_key);
+    }
+  }
+  /// Checks if an item is in the counting Bloom filter.
+  pub fn contains(&self, item: &T) -> bool {
+    for h_key in &self.hash_keys {
+      if !self.check_counter(item, *h_key) {
+        return false;
+      }
+    }
+    true
+  }
+  // clear all the counters
+  pub fn reset(&self) {
+    for n in &self.counters{
+      n.store(0, Ordering::Relaxed);
+    }
+    self.n_nonzero.store(0, Ordering::Relaxed);
+  }
+  // get the number of non-zero counters
+  pub fn num_bits_set(&self) ->u64 {
+    self.n_nonzero.load(Ordering::Relaxed)
+  }
 }
 
 #[cfg(test)]
 mod test {
     use {
-      super::Bloom, 
-      rand::{rng, rngs::ThreadRng, Rng}, 
+      super::{Bloom, BloomError, CountingBloom},
+      rand::{rng, rngs::ThreadRng, Rng},
       rayon::prelude::*, 
       std::sync::atomic::{AtomicU64, Ordering},
     };
   #[test]
   fn test_bloom_constructor() {
     let bloom: Bloom<String> = Bloom::new(0, 0.1);
-    assert_eq!(bloom.n_bits, 64);
-    assert_eq!(bloom.hash_keys.len(), 3);
+    assert_eq!(bloom.n_bits, 512);
+    assert_eq!(bloom.k, 3);
 
     let bloom: Bloom<String> = Bloom::new(10, 0.1);
-    assert_eq!(bloom.n_bits, 64);
-    assert_eq!(bloom.hash_keys.len(), 3);
+    assert_eq!(bloom.n_bits, 512);
+    assert_eq!(bloom.k, 3);
 
     let bloom: Bloom<String> = Bloom::new(100, 0.1);
     assert_eq!(bloom.n_bits, 512);
-    assert_eq!(bloom.hash_keys.len(), 3);
+    assert_eq!(bloom.k, 3);
   }
   #[test]
   fn test_bloom_hash_keys_randomness() {
-    let mut bloom1: Bloom<String> = Bloom::new(10, 0.1);
-    let mut bloom2: Bloom<String> = Bloom::new(10, 0.1);
-    assert_eq!(bloom1.hash_keys.len(), bloom2.hash_keys.len());
-    bloom1.hash_keys.sort_unstable();
-    bloom2.hash_keys.sort_unstable();
+    let bloom1: Bloom<String> = Bloom::new(10, 0.1);
+    let bloom2: Bloom<String> = Bloom::new(10, 0.1);
+    assert_eq!(bloom1.k, bloom2.k);
     assert_ne!(bloom1.hash_keys, bloom2.hash_keys);
   }
   const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
@@ -155,8 +425,8 @@ mod test {
   fn test_bloom_insert_contains() {
     let bloom: Bloom<String> = Bloom::new(2100, 0.1);
     println!("{:?}", bloom);
-    assert_eq!(10112, bloom.n_bits);
-    assert_eq!(3, bloom.hash_keys.len());
+    assert_eq!(16384, bloom.n_bits);
+    assert_eq!(3, bloom.k);
     let mut r = rng();
     let items: Vec<String> = (0..2000).map(|_| random_string(&mut r)).collect();
 
@@ -179,8 +449,104 @@ mod test {
         false_positives.fetch_add(1, Ordering::Relaxed);
       }
     });
-    assert!(false_positives.load(Ordering::Relaxed) < 2000, 
+    assert!(false_positives.load(Ordering::Relaxed) < 2000,
     "false_positive: {}", false_positives.load(Ordering::Relaxed));
   }
+  #[test]
+  fn test_counting_bloom_insert_remove() {
+    let bloom: CountingBloom<String> = CountingBloom::new(100, 0.1);
+    let item = "hello".to_string();
+    assert!(!bloom.contains(&item));
+    bloom.insert(&item);
+    assert!(bloom.contains(&item));
+    // inserting twice then removing once still leaves the item present
+    bloom.insert(&item);
+    bloom.remove(&item);
+    assert!(bloom.contains(&item));
+    // the final remove drops every counter back to zero
+    bloom.remove(&item);
+    assert!(!bloom.contains(&item));
+    assert_eq!(0, bloom.num_bits_set());
+  }
+  #[test]
+  fn test_counting_bloom_saturation_never_removed() {
+    let bloom: CountingBloom<String> = CountingBloom::new(100, 0.1);
+    let item = "saturate".to_string();
+    for _ in 0..300 {
+      bloom.insert(&item); // drive every counter to the 255 cap
+    }
+    // a saturated counter is pinned, so no number of removes can evict it
+    for _ in 0..300 {
+      bloom.remove(&item);
+    }
+    assert!(bloom.contains(&item));
+  }
+  #[test]
+  fn test_bloom_union_intersect() {
+    let a: Bloom<String> = Bloom::new(100, 0.1);
+    // give `b` the same hash parameters so the two filters are combinable
+    let mut b: Bloom<String> = Bloom::new(100, 0.1);
+    b.hash_keys = a.hash_keys;
+    let x = "x".to_string();
+    let y = "y".to_string();
+    a.insert(&x);
+    b.insert(&y);
+
+    let u: Bloom<String> = Bloom::new(100, 0.1);
+    let mut u = u;
+    u.hash_keys = a.hash_keys;
+    u.union_with(&a).unwrap();
+    u.union_with(&b).unwrap();
+    assert!(u.contains(&x));
+    assert!(u.contains(&y));
+
+    let i: Bloom<String> = Bloom::new(100, 0.1);
+    let mut i = i;
+    i.hash_keys = a.hash_keys;
+    i.union_with(&a).unwrap();
+    i.intersect_with(&b).unwrap();
+    assert!(!i.contains(&x));
+
+    // combining filters with different parameters is rejected
+    let other: Bloom<String> = Bloom::new(5000, 0.1);
+    assert_eq!(Err(BloomError::Mismatched), a.union_with(&other));
+  }
+  #[test]
+  fn test_bloom_estimate_cardinality() {
+    let bloom: Bloom<String> = Bloom::new(2100, 0.1);
+    let mut r = rng();
+    let items: Vec<String> = (0..1000).map(|_| random_string(&mut r)).collect();
+    for item in &items {
+      bloom.insert(item);
+    }
+    let est = bloom.estimate_cardinality();
+    // the estimate should land within 10% of the true distinct count
+    assert!((est - 1000.0).abs() < 100.0, "estimate: {}", est);
+  }
+  #[test]
+  fn test_bloom_new_capped() {
+    // without a cap this filter would need far more than 1024 bits
+    let bloom: Bloom<String> = Bloom::new_capped(100000, 0.01, 1024);
+    assert!(bloom.n_bits <= 1024);
+    assert!(bloom.n_bits.is_power_of_two());
+    assert!(bloom.k >= 1);
+    bloom.validate().unwrap();
+    let item = "capped".to_string();
+    bloom.insert(&item);
+    assert!(bloom.contains(&item));
+  }
+  #[test]
+  fn test_bloom_validate_rejects_bad_parts() {
+    // zero bits
+    assert_eq!(
+      Some(BloomError::Invalid("n_bits must be greater than zero")),
+      Bloom::<String>::from_parts(0, [1, 2], 3, vec![]).err(),
+    );
+    // bits length inconsistent with n_bits
+    assert!(Bloom::<String>::from_parts(512, [1, 2], 3, vec![0u64; 2]).is_err());
+    // a well-formed set of parts round-trips
+    let ok = Bloom::<String>::from_parts(512, [1, 2], 3, vec![0u64; 8]);
+    assert!(ok.is_ok());
+  }
 }
 